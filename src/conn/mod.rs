@@ -0,0 +1,12 @@
+impl MyConn {
+    /// Cheap, non-blocking liveness check.
+    ///
+    /// Unlike `ping`, this issues no network traffic: it only inspects whether
+    /// the underlying stream is still around. The stream is taken out of the
+    /// connection when it is shut down or after an I/O error, so a missing
+    /// stream means the socket is dead and the connection must be discarded
+    /// rather than returned to the pool.
+    pub fn has_broken(&self) -> bool {
+        self.stream.is_none()
+    }
+}
@@ -1,19 +1,33 @@
+use std::io::timer;
+use std::time::duration::Duration;
+use time::{Timespec, get_time};
 use sync::{Arc, Mutex};
-use super::super::error::{MyDriverError, InvalidPoolConstraints};
+use super::super::error::{MyDriverError, InvalidPoolConstraints, ConnectionTimeout};
 use super::{MyConn, MyOpts, Stmt, QueryResult};
 use super::super::error::{MyResult};
 
 struct MyInnerPool {
     opts: MyOpts,
-    pool: Vec<MyConn>,
+    // Idle connections paired with the instant at which they were returned to
+    // the pool, so the reaper can tell how long each one has been sitting.
+    pool: Vec<(Timespec, MyConn)>,
     min: uint,
     max: uint,
-    count: uint
+    count: uint,
+    idle_timeout: Option<Duration>,
+    verify_conn: bool,
+    // SQL run on every connection right after it is opened or reset, so that
+    // `USE db`, `SET NAMES utf8`, session variables and the like hold no
+    // matter which connection the pool hands out.
+    init: Vec<String>
 }
 
 impl MyInnerPool {
-    fn new(min: uint, max: uint, opts: MyOpts) -> MyResult<MyInnerPool> {
-        if min > max || max == 0 {
+    fn new(min: uint, max: uint, initial: uint, opts: MyOpts,
+           idle_timeout: Option<Duration>,
+           verify_conn: bool,
+           init: Vec<String>) -> MyResult<MyInnerPool> {
+        if min > max || max == 0 || initial < min || initial > max {
             return Err(MyDriverError(InvalidPoolConstraints));
         }
         let mut pool = MyInnerPool {
@@ -21,22 +35,60 @@ impl MyInnerPool {
             pool: Vec::with_capacity(max),
             max: max,
             min: min,
-            count: 0
+            count: initial,
+            idle_timeout: idle_timeout,
+            verify_conn: verify_conn,
+            init: init
         };
-        for _ in range(0, min) {
+        for _ in range(0, initial) {
             try!(pool.new_conn());
         }
         Ok(pool)
     }
     fn new_conn(&mut self) -> MyResult<()> {
         match MyConn::new(self.opts.clone()) {
-            Ok(conn) => {
-                self.pool.push(conn);
+            Ok(mut conn) => {
+                try!(run_init(&mut conn, self.init.as_slice()));
+                self.pool.push((get_time(), conn));
                 Ok(())
             },
             Err(err) => Err(err)
         }
     }
+
+    /// Closes idle connections older than `idle_timeout`, never dropping below
+    /// `min`.
+    fn reap_idle(&mut self) {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return
+        };
+        let now = get_time();
+        let timeout_ms = timeout.num_milliseconds();
+        while self.count > self.min && !self.pool.is_empty() {
+            // Compute elapsed milliseconds from the `Timespec` fields rather
+            // than subtracting `Timespec`s, whose `Sub` impl we can't rely on
+            // in this `time` version. Whole seconds would truncate sub-second
+            // timeouts to zero and reap everything on every checkout.
+            let front = self.pool[0].ref0();
+            let elapsed_ms = (now.sec - front.sec) * 1000
+                           + (now.nsec - front.nsec) as i64 / 1_000_000;
+            if elapsed_ms < timeout_ms {
+                break;
+            }
+            self.pool.remove(0);
+            self.count -= 1;
+        }
+    }
+}
+
+/// Runs each initialization statement on `conn`, bailing out on the first
+/// error. Used on freshly opened and freshly reset connections.
+fn run_init(conn: &mut MyConn, init: &[String]) -> MyResult<()> {
+    for stmt in init.iter() {
+        try!(conn.query(stmt.as_slice()));
+    }
+    Ok(())
 }
 
 /// Pool which is holding mysql connections.
@@ -77,12 +129,19 @@ pub struct MyPool {
 impl MyPool {
     /// Creates new pool with `min = 10` and `max = 100`.
     pub fn new(opts: MyOpts) -> MyResult<MyPool> {
-        MyPool::new_manual(10, 100, opts)
+        MyPool::new_manual(10, 100, 10, opts, None, true, Vec::new())
     }
 
-    /// Same as `new` but you can set `min` and `max`.
-    pub fn new_manual(min: uint, max: uint, opts: MyOpts) -> MyResult<MyPool> {
-        let pool = try!(MyInnerPool::new(min, max, opts));
+    /// Same as `new` but lets you set the `min`/`max`/startup `initial` counts
+    /// (`min <= initial <= max`), an optional `idle_timeout`, whether
+    /// connections are pinged on checkout (`verify_conn`), and `init`
+    /// statements run on every connection.
+    pub fn new_manual(min: uint, max: uint, initial: uint, opts: MyOpts,
+                      idle_timeout: Option<Duration>,
+                      verify_conn: bool,
+                      init: Vec<String>) -> MyResult<MyPool> {
+        let pool = try!(MyInnerPool::new(min, max, initial, opts, idle_timeout,
+                                         verify_conn, init));
         Ok(MyPool{ pool: Arc::new(Mutex::new(pool)) })
     }
 
@@ -93,6 +152,8 @@ impl MyPool {
     pub fn get_conn(&self) -> MyResult<MyPooledConn> {
         let mut pool = self.pool.lock();
 
+        pool.reap_idle();
+
         while pool.pool.is_empty() {
             if pool.count < pool.max {
                 match pool.new_conn() {
@@ -107,10 +168,61 @@ impl MyPool {
             }
         }
 
-        let mut conn = pool.pool.pop().unwrap();
+        let verify = pool.verify_conn;
+        let init = pool.init.clone();
+        let (_, conn) = pool.pool.pop().unwrap();
+        self.wrap_conn(conn, verify, init)
+    }
+
+    /// Like `get_conn` but returns `Ok(None)` instead of blocking when the
+    /// pool is saturated.
+    pub fn try_get_conn(&self) -> MyResult<Option<MyPooledConn>> {
+        let mut pool = self.pool.lock();
+
+        pool.reap_idle();
+
+        if pool.pool.is_empty() {
+            if pool.count < pool.max {
+                try!(pool.new_conn());
+                pool.count += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+
+        let verify = pool.verify_conn;
+        let init = pool.init.clone();
+        let (_, conn) = pool.pool.pop().unwrap();
+        self.wrap_conn(conn, verify, init).map(|conn| Some(conn))
+    }
+
+    /// Like `get_conn` but waits at most `timeout`, then fails with
+    /// `ConnectionTimeout`.
+    pub fn get_conn_timeout(&self, timeout: Duration) -> MyResult<MyPooledConn> {
+        let step = Duration::milliseconds(5);
+        let mut left = timeout;
+        loop {
+            match try!(self.try_get_conn()) {
+                Some(conn) => return Ok(conn),
+                None => {
+                    if left <= Duration::zero() {
+                        return Err(MyDriverError(ConnectionTimeout));
+                    }
+                    let nap = if left < step { left } else { step };
+                    timer::sleep(nap);
+                    left = left - nap;
+                }
+            }
+        }
+    }
 
-        if !conn.ping() {
+    /// Wraps `conn` in a `MyPooledConn`. Unless `verify` is false, it is pinged
+    /// first and, if the ping fails, reset and given the `init` statements.
+    fn wrap_conn(&self, mut conn: MyConn, verify: bool,
+                 init: Vec<String>) -> MyResult<MyPooledConn> {
+        if verify && !conn.ping() {
             try!(conn.reset());
+            try!(run_init(&mut conn, init.as_slice()));
         }
 
         Ok(MyPooledConn {pool: self.clone(), conn: Some(conn)})
@@ -146,6 +258,13 @@ impl MyPool {
         let conn = try!(self.get_conn());
         conn.pooled_prepare(query)
     }
+
+    /// Checks out a connection, issues `BEGIN` and pins it to a `Transaction`.
+    pub fn start_transaction(&self) -> MyResult<Transaction> {
+        let mut conn = try!(self.get_conn());
+        try!(conn.query("BEGIN"));
+        Ok(Transaction { conn: conn, finished: false })
+    }
 }
 
 /// Pooled mysql connection which will return to the pool at the end of its
@@ -158,10 +277,11 @@ pub struct MyPooledConn {
 impl Drop for MyPooledConn {
     fn drop(&mut self) {
         let mut pool = self.pool.pool.lock();
-        if pool.count > pool.min || self.conn.is_none() {
+        let broken = self.conn.as_ref().map_or(true, |conn| conn.has_broken());
+        if broken || pool.count > pool.min {
             pool.count -= 1;
         } else {
-            pool.pool.push(self.conn.take_unwrap());
+            pool.pool.push((get_time(), self.conn.take_unwrap()));
         }
         pool.cond.signal();
     }
@@ -211,13 +331,61 @@ impl MyPooledConn {
     }
 }
 
+/// Transaction pinned to a single pooled connection.
+///
+/// Dropping it without calling `commit` or `rollback` rolls back.
+pub struct Transaction {
+    conn: MyPooledConn,
+    finished: bool
+}
+
+impl Transaction {
+    /// Runs `query` on the pinned connection. See `MyPooledConn#query`.
+    pub fn query<'a>(&'a mut self, query: &str) -> MyResult<QueryResult<'a>> {
+        self.conn.query(query)
+    }
+
+    /// Prepares `query` on the pinned connection. See `MyPooledConn#prepare`.
+    pub fn prepare<'a>(&'a mut self, query: &str) -> MyResult<Stmt<'a>> {
+        self.conn.prepare(query)
+    }
+
+    /// Commits the transaction.
+    pub fn commit(mut self) -> MyResult<()> {
+        try!(self.conn.query("COMMIT"));
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Rolls the transaction back.
+    pub fn rollback(mut self) -> MyResult<()> {
+        try!(self.conn.query("ROLLBACK"));
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.conn.query("ROLLBACK");
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use conn::{MyOpts};
     use std::default::{Default};
+    use std::io::timer;
+    use std::time::duration::Duration;
     use super::{MyPool};
     use super::super::super::value::{Bytes, Int};
 
+    fn opts() -> MyOpts {
+        MyOpts{user: Some("root".to_string()), ..Default::default()}
+    }
+
     #[test]
     fn test_query() {
         let pool = MyPool::new(MyOpts{user: Some("root".to_string()),
@@ -294,4 +462,110 @@ mod test {
             });
         }
     }
+
+    #[test]
+    fn test_idle_reaping() {
+        let pool = MyPool::new_manual(1, 5, 3, opts(),
+                                      Some(Duration::milliseconds(500)), true,
+                                      Vec::new());
+        assert!(pool.is_ok());
+        let pool = pool.unwrap();
+        timer::sleep(Duration::milliseconds(800));
+        // The next checkout reaps the two surplus startup connections, leaving
+        // `min`, and still hands back a live connection.
+        let mut conn = pool.get_conn().unwrap();
+        assert!(conn.query("SELECT 1").is_ok());
+        assert_eq!(pool.pool.lock().count, 1);
+    }
+
+    #[test]
+    fn test_try_get_conn() {
+        let pool = MyPool::new_manual(1, 1, 1, opts(), None, true, Vec::new());
+        assert!(pool.is_ok());
+        let pool = pool.unwrap();
+        let conn = pool.try_get_conn();
+        assert!(conn.is_ok());
+        let conn = conn.unwrap();
+        assert!(conn.is_some());
+        // The only connection is checked out and `max == 1`, so the pool is
+        // saturated and the next attempt returns `None` instead of blocking.
+        assert!(pool.try_get_conn().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_conn_timeout() {
+        let pool = MyPool::new_manual(1, 1, 1, opts(), None, true, Vec::new());
+        assert!(pool.is_ok());
+        let pool = pool.unwrap();
+        let _conn = pool.get_conn().unwrap();
+        // No connection can become free, so the bounded wait gives up.
+        assert!(pool.get_conn_timeout(Duration::milliseconds(200)).is_err());
+    }
+
+    #[test]
+    fn test_healthy_conn_kept_on_return() {
+        let pool = MyPool::new_manual(1, 2, 1, opts(), None, false, Vec::new());
+        assert!(pool.is_ok());
+        let pool = pool.unwrap();
+        {
+            let mut conn = pool.get_conn().unwrap();
+            assert!(conn.query("SELECT 1").is_ok());
+            // A healthy connection reports not broken, so it is returned to the
+            // pool rather than discarded.
+            assert!(!conn.get_ref().has_broken());
+        }
+        assert_eq!(pool.pool.lock().pool.len(), 1);
+        let mut conn = pool.get_conn().unwrap();
+        assert!(conn.query("SELECT 1").is_ok());
+    }
+
+    #[test]
+    fn test_init_hook() {
+        let pool = MyPool::new_manual(1, 1, 1, opts(), None, true,
+                                      vec!["SET @answer := 42".to_string()]);
+        assert!(pool.is_ok());
+        let pool = pool.unwrap();
+        let mut conn = pool.get_conn().unwrap();
+        let mut result = conn.query("SELECT @answer").unwrap();
+        assert_eq!(result.next(), Some(Ok(vec![Bytes(vec![0x34u8, 0x32u8])])));
+    }
+
+    #[test]
+    fn test_transaction_commit() {
+        let pool = MyPool::new(opts()).unwrap();
+        let mut t = pool.start_transaction().unwrap();
+        assert!(t.query("SELECT 1").is_ok());
+        assert!(t.commit().is_ok());
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let pool = MyPool::new(opts()).unwrap();
+        let mut t = pool.start_transaction().unwrap();
+        assert!(t.query("SELECT 1").is_ok());
+        assert!(t.rollback().is_ok());
+    }
+
+    #[test]
+    fn test_transaction_drop_rollback() {
+        let pool = MyPool::new(opts()).unwrap();
+        {
+            let mut t = pool.start_transaction().unwrap();
+            assert!(t.query("SELECT 1").is_ok());
+            // Dropped without commit/rollback: the guard rolls back and the
+            // connection returns to the pool.
+        }
+        assert!(pool.get_conn().is_ok());
+    }
+
+    #[test]
+    fn test_initial_size() {
+        // `initial` may differ from `min` as long as it stays in `[min, max]`.
+        assert!(MyPool::new_manual(1, 5, 3, opts(), None, true,
+                                   Vec::new()).is_ok());
+        assert!(MyPool::new_manual(2, 5, 1, opts(), None, true,
+                                   Vec::new()).is_err());
+        assert!(MyPool::new_manual(1, 5, 6, opts(), None, true,
+                                   Vec::new()).is_err());
+    }
 }
@@ -0,0 +1,57 @@
+use std::io::IoError;
+use std::fmt;
+
+/// Errors raised by the driver itself rather than by the MySQL server.
+#[deriving(PartialEq, Eq, Clone)]
+pub enum MyDriverError {
+    CouldNotConnect(String),
+    UnexpectedPacket,
+    Protocol41NotSet,
+    UnsupportedProtocol(u8),
+    PacketOutOfOrder,
+    MismatchedStmtParams(u16, uint),
+    SetupError,
+    InvalidPoolConstraints,
+    /// No connection became free within the requested timeout.
+    ConnectionTimeout,
+}
+
+impl fmt::Show for MyDriverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CouldNotConnect(ref desc) =>
+                write!(f, "Could not connect: {}", desc),
+            UnexpectedPacket => write!(f, "Unexpected packet"),
+            Protocol41NotSet => write!(f, "Server does not set PROTOCOL_41 flag"),
+            UnsupportedProtocol(proto) =>
+                write!(f, "Unsupported protocol version {}", proto),
+            PacketOutOfOrder => write!(f, "Packet out of order"),
+            MismatchedStmtParams(exp, got) =>
+                write!(f, "Statement takes {} parameters but {} were supplied",
+                       exp, got),
+            SetupError => write!(f, "Could not set up connection"),
+            InvalidPoolConstraints => write!(f, "Invalid pool constraints"),
+            ConnectionTimeout => write!(f, "Timed out waiting for a connection"),
+        }
+    }
+}
+
+/// Any error that can be raised while talking to MySQL.
+#[deriving(PartialEq, Eq, Clone)]
+pub enum MyError {
+    MyIoError(IoError),
+    MyDriverError(MyDriverError),
+    MySqlError(u16, String),
+}
+
+impl fmt::Show for MyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MyIoError(ref err) => err.fmt(f),
+            MyDriverError(ref err) => err.fmt(f),
+            MySqlError(code, ref msg) => write!(f, "ERROR {}: {}", code, msg),
+        }
+    }
+}
+
+pub type MyResult<T> = Result<T, MyError>;